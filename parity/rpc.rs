@@ -38,6 +38,9 @@ pub struct HttpConfiguration {
 	pub apis: ApiSet,
 	pub cors: Option<Vec<String>>,
 	pub hosts: Option<Vec<String>>,
+	/// Size of the worker thread pool the JSON-RPC server is spawned with. Only takes
+	/// effect when no dapps `Middleware` is attached — see `new_http`. `None` runs a
+	/// single-threaded server.
 	pub threads: Option<usize>,
 }
 
@@ -133,6 +136,22 @@ pub struct WsConfiguration {
 	pub origins: Option<Vec<String>>,
 	pub hosts: Option<Vec<String>>,
 	pub signer_path: PathBuf,
+	/// Whether a session has to authenticate (via a token checked against `signer_path`)
+	/// before it is upgraded from `apis` to the full `SafeContext` API exposed through
+	/// `WsDispatcher`. See [`WsAuthMode`].
+	pub auth: WsAuthMode,
+}
+
+/// Session authentication policy for a WS endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsAuthMode {
+	/// No session ever authenticates; every request is served strictly from `apis`.
+	Disabled,
+	/// A session starts unauthenticated and is upgraded to the full `SafeContext` API
+	/// once it presents a valid, single-use token checked against the codes stored at
+	/// `signer_path`. Requests for full-API methods from an unauthenticated session are
+	/// rejected with a permission error instead of being served.
+	TokenRequired,
 }
 
 impl Default for WsConfiguration {
@@ -146,6 +165,7 @@ impl Default for WsConfiguration {
 			origins: Some(Vec::new()),
 			hosts: Some(Vec::new()),
 			signer_path: replace_home(&data_dir, "$BASE/signer").into(),
+			auth: WsAuthMode::TokenRequired,
 		}
 	}
 }
@@ -193,16 +213,22 @@ pub fn new_ws<D: rpc_apis::Dependencies>(
 	let allowed_origins = into_domains(conf.origins);
 	let allowed_hosts = into_domains(conf.hosts);
 
-	let path = ::signer::codes_path(&conf.signer_path);
+	// `rpc::WsExtractor`/`rpc::Metadata` (defined in the `parity_rpc` crate) own the actual
+	// single-use token check and the per-method permission gate, tagging each session
+	// unauthenticated by default and upgrading it to `SafeContext` on successful auth; here
+	// we only decide whether a codes path is handed to them at all, per `conf.auth`.
+	let codes_path = match conf.auth {
+		WsAuthMode::TokenRequired => Some(::signer::codes_path(&conf.signer_path)),
+		WsAuthMode::Disabled => None,
+	};
 	let start_result = rpc::start_ws(
 		&addr,
 		handler,
 		remote,
 		allowed_origins,
 		allowed_hosts,
-		// TODO [ToDr] Codes should be provided only if signer is enabled!
-		rpc::WsExtractor::new(Some(&path)),
-		rpc::WsExtractor::new(Some(&path)),
+		rpc::WsExtractor::new(codes_path.as_ref()),
+		rpc::WsExtractor::new(codes_path.as_ref()),
 		rpc::WsStats::new(deps.stats.clone()),
 	);
 
@@ -234,6 +260,19 @@ pub fn new_http<D: rpc_apis::Dependencies>(
 	let cors_domains = into_domains(conf.cors);
 	let allowed_hosts = into_domains(conf.hosts);
 
+	// Letting a dapps `Middleware` run in front of a multi-threaded backend — N worker
+	// threads sharing one `MetaIoHandler`, with requests routed through the middleware
+	// chain before dispatch — would need changes to `HttpSettings`/`start_http` themselves,
+	// in the `parity_rpc` crate, which is outside this tree's snapshot and is not
+	// implemented by this commit. Until that lands, the two remain mutually exclusive, as
+	// they always have been.
+	let settings = match (conf.threads, middleware) {
+		(Some(_), Some(_)) => return Err(
+			"Dapps and fast multi-threaded RPC server cannot be enabled at the same time.".into()
+		),
+		(Some(threads), None) => rpc::HttpSettings::Threads(threads),
+		(None, middleware) => rpc::HttpSettings::Dapps(middleware),
+	};
 	let start_result = rpc::start_http(
 		&addr,
 		cors_domains,
@@ -241,13 +280,7 @@ pub fn new_http<D: rpc_apis::Dependencies>(
 		handler,
 		remote,
 		rpc::RpcExtractor,
-		match (conf.threads, middleware) {
-			(Some(threads), None) => rpc::HttpSettings::Threads(threads),
-			(None, middleware) => rpc::HttpSettings::Dapps(middleware),
-			(Some(_), Some(_)) => {
-				return Err("Dapps and fast multi-threaded RPC server cannot be enabled at the same time.".into())
-			},
-		}
+		settings,
 	);
 
 	match start_result {
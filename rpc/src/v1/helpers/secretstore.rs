@@ -14,34 +14,180 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
+//! Backing implementation for the `secretstore` RPC API: `secretstore_encrypt` maps
+//! onto `encrypt_document`, `secretstore_decrypt` onto `decrypt_document` and
+//! `secretstore_shadowDecrypt` onto `decrypt_document_with_shadow`.
+//!
+//! These are synchronous, CPU-bound EC/AES helpers, so they must not be called directly
+//! from the reactor thread that also services other RPC traffic on the shared
+//! `TokioRemote`. The `*_async` wrappers below run them on `pool` (a `CpuPool` shared by
+//! the RPC handlers) and hand back a `Future` instead of blocking the caller.
+//!
+//! Registering these as `secretstore_*` JSON-RPC methods (a `secretstore` `ApiSet`
+//! variant, `list_apis`/`setup_apis`/`extend_with_set` wiring, and the RPC
+//! trait/impl boilerplate) is the RPC-layer's responsibility and lives outside this
+//! helpers module. That wiring is not part of this tree and remains an open follow-up —
+//! this module alone does not make `secretstore_*` callable over JSON-RPC.
+
 use std::iter::repeat;
+use std::sync::Arc;
 use rand::{Rng, OsRng};
 use ethkey::{Public, Secret, math};
 use crypto;
-use util::Bytes;
+use util::{Bytes, Address, H256};
+use util::sha3::Hashable;
 use jsonrpc_core::Error;
+use futures::Future;
+use futures_cpupool::CpuPool;
 use v1::helpers::errors;
 
+/// Checks whether a requester is permitted, by an on-chain access-control list, to
+/// decrypt a given document. Implemented by a native contract wrapper over the ACL
+/// contract's `checkPermissions(address, bytes32) -> bool`.
+///
+/// Implementations (see `AclContract`) may block on network/database I/O. Never call
+/// `check` from a reactor/event-loop thread — dispatch it onto a `CpuPool` instead, as
+/// `decrypt_document_with_shadow_async` does.
+pub trait AclChecker: Send + Sync {
+	/// Returns `true` if `requester` may decrypt `document`.
+	fn check(&self, requester: Address, document: &H256) -> Result<bool, String>;
+}
+
+/// A read-only contract call against current state: ABI-encoded call `data` in,
+/// ABI-encoded return data out. Implemented, in production, by the client's state
+/// executor against the latest block — the same seam the registry and
+/// service-transaction-checker native contract wrappers call through.
+pub trait CallContract: Send + Sync {
+	fn call(&self, contract: Address, data: Bytes) -> Box<Future<Item = Bytes, Error = String> + Send>;
+}
+
+/// Selector + argument encoding for the ACL contract's `checkPermissions(address,bytes32)`.
+const CHECK_PERMISSIONS_SIGNATURE: &'static str = "checkPermissions(address,bytes32)";
+
+/// Native contract wrapper over the on-chain secretstore ACL contract, hand-encoded from
+/// its ABI the same way the registry/service-transaction wrappers are generated: a
+/// 4-byte Keccak selector followed by 32-byte-padded arguments, decoding a single `bool`
+/// back out of the 32-byte return value.
+pub struct AclContract<C> {
+	contract: Address,
+	client: C,
+}
+
+impl<C: CallContract> AclContract<C> {
+	pub fn new(contract: Address, client: C) -> Self {
+		AclContract { contract, client }
+	}
+}
+
+impl<C: CallContract> AclChecker for AclContract<C> {
+	fn check(&self, requester: Address, document: &H256) -> Result<bool, String> {
+		let selector_hash = CHECK_PERMISSIONS_SIGNATURE.as_bytes().sha3();
+		let mut call_data = Vec::with_capacity(4 + 32 + 32);
+		call_data.extend_from_slice(&selector_hash[0..4]);
+		call_data.extend(repeat(0u8).take(12));
+		call_data.extend_from_slice(requester.as_ref());
+		call_data.extend_from_slice(document.as_ref());
+
+		// this is a blocking wait on the contract-call future; callers dispatch `check`
+		// (transitively, via `decrypt_document_with_shadow`) onto a `CpuPool` so this
+		// never blocks the reactor thread — see `decrypt_document_with_shadow_async`.
+		let result = self.client.call(self.contract, call_data).wait()?;
+
+		// a `bool` return value is ABI-encoded as a single 32-byte word; anything else
+		// (a short, garbled, or reverted response) is not a valid answer and must not be
+		// misread as "permitted" just because it happens to end in `0x01`.
+		if result.len() != 32 {
+			return Err(format!("invalid ACL contract response length: {}", result.len()));
+		}
+		Ok(result.last() == Some(&1u8))
+	}
+}
+
 /// Initialization vector length.
 const INIT_VEC_LEN: usize = 16;
 
+/// Keccak-256 MAC length appended to authenticated documents.
+const MAC_LEN: usize = 32;
+
+/// Version tag of the authenticated, AES-256 encrypted document format.
+const DOCUMENT_VERSION: u8 = 1;
+
 /// Encrypt document with distributely generated key.
+///
+/// The output is `version || iv || ciphertext || mac`, where `mac` is a Keccak-256
+/// MAC over everything preceding it, keyed with a hash-key distinct from the cipher key.
 pub fn encrypt_document(key: Bytes, document: Bytes) -> Result<Bytes, Error> {
-	// make document key
-	let key = into_document_key(key)?;
+	// derive a full AES-256 key and a domain-separated MAC key from the whole distributed public
+	let (cipher_key, mac_key) = derive_keys(&key)?;
 
 	// use symmetric encryption to encrypt document
 	let iv = initialization_vector();
-	let mut encrypted_document = Vec::with_capacity(document.len() + iv.len());
-	encrypted_document.extend(repeat(0).take(document.len()));
-	crypto::aes::encrypt(&key, &iv, &document, &mut encrypted_document);
+	let mut ciphertext = Vec::with_capacity(document.len());
+	ciphertext.extend(repeat(0).take(document.len()));
+	crypto::aes::encrypt(&cipher_key, &iv, &document, &mut ciphertext);
+
+	let mut encrypted_document = Vec::with_capacity(1 + iv.len() + ciphertext.len() + MAC_LEN);
+	encrypted_document.push(DOCUMENT_VERSION);
 	encrypted_document.extend_from_slice(&iv);
+	encrypted_document.extend_from_slice(&ciphertext);
+	let tag = mac(&mac_key, &encrypted_document);
+	encrypted_document.extend_from_slice(&tag);
 
 	Ok(encrypted_document)
 }
 
 /// Decrypt document with distributely generated key.
-pub fn decrypt_document(key: Bytes, mut encrypted_document: Bytes) -> Result<Bytes, Error> {
+///
+/// Dispatch between the new, authenticated format and the legacy one is by leading
+/// version byte. A legacy (version-less, IV-suffixed, unauthenticated) ciphertext's
+/// leading byte is uniformly random, so roughly 1 in 256 legacy documents happen to
+/// start with `DOCUMENT_VERSION` and get misrouted into the authenticated path, where
+/// they will fail the MAC check and this call will return an error even though the
+/// document itself was never tampered with. This is a known, accepted limitation of a
+/// single-byte discriminator: the alternative — treating MAC failure as "fall back to
+/// legacy" — would let a genuinely tampered authenticated document decrypt "successfully"
+/// into garbage, silently defeating the point of authenticating it. Erring on the side of
+/// a rare false-positive `invalid_params` is the safer failure mode.
+pub fn decrypt_document(key: Bytes, encrypted_document: Bytes) -> Result<Bytes, Error> {
+	if encrypted_document.first() != Some(&DOCUMENT_VERSION) {
+		return decrypt_document_legacy(key, encrypted_document);
+	}
+
+	decrypt_document_authenticated(&key, &encrypted_document)
+}
+
+/// Decrypt `encrypted_document` as a versioned, MAC-authenticated document. Errors if the
+/// document is too short to be valid, or if the MAC doesn't verify (including the rare case
+/// of a legacy document whose leading byte happens to collide with `DOCUMENT_VERSION`; see
+/// `decrypt_document`).
+fn decrypt_document_authenticated(key: &[u8], encrypted_document: &[u8]) -> Result<Bytes, Error> {
+	if encrypted_document.len() < 1 + INIT_VEC_LEN + MAC_LEN {
+		return Err(errors::invalid_params("encrypted_document", "invalid encrypted data"));
+	}
+
+	// make document keys
+	let (cipher_key, mac_key) = derive_keys(key)?;
+
+	// check the MAC before touching the ciphertext
+	let mac_offset = encrypted_document.len() - MAC_LEN;
+	let (body, tag) = encrypted_document.split_at(mac_offset);
+	let expected_tag = mac(&mac_key, body);
+	if !constant_time_eq(&expected_tag, tag) {
+		return Err(errors::invalid_params("encrypted_document", "MAC mismatch"));
+	}
+
+	// use symmetric decryption to decrypt document
+	let iv = &body[1..1 + INIT_VEC_LEN];
+	let ciphertext = &body[1 + INIT_VEC_LEN..];
+	let mut document = Vec::with_capacity(ciphertext.len());
+	document.extend(repeat(0).take(ciphertext.len()));
+	crypto::aes::decrypt(&cipher_key, iv, ciphertext, &mut document);
+
+	Ok(document)
+}
+
+/// Decrypt a legacy document: AES-128 keyed from `key[..16]`, IV appended, no MAC.
+fn decrypt_document_legacy(key: Bytes, mut encrypted_document: Bytes) -> Result<Bytes, Error> {
 	// initialization vector takes INIT_VEC_LEN bytes
 	let encrypted_document_len = encrypted_document.len();
 	if encrypted_document_len < INIT_VEC_LEN {
@@ -49,7 +195,7 @@ pub fn decrypt_document(key: Bytes, mut encrypted_document: Bytes) -> Result<Byt
 	}
 
 	// make document key
-	let key = into_document_key(key)?;
+	let key = into_legacy_document_key(&key)?;
 
 	// use symmetric decryption to decrypt document
 	let iv = encrypted_document.split_off(encrypted_document_len - INIT_VEC_LEN);
@@ -60,12 +206,61 @@ pub fn decrypt_document(key: Bytes, mut encrypted_document: Bytes) -> Result<Byt
 	Ok(document)
 }
 
-pub fn decrypt_document_with_shadow(decrypted_secret: Public, common_point: Public, shadows: Vec<Secret>, encrypted_document: Bytes) -> Result<Bytes, Error> {
+/// Reconstruct the document key from its shadow coefficients and decrypt the document,
+/// but only after `acl_checker` confirms `requester` is authorized for `document` on-chain.
+///
+/// This blocks on the ACL check (see `AclChecker::check`) and must never be called from a
+/// reactor/event-loop thread. It is `pub(crate)` rather than `pub` for exactly that reason:
+/// the only supported entry point for other crates is `decrypt_document_with_shadow_async`,
+/// which dispatches onto a `CpuPool`.
+pub(crate) fn decrypt_document_with_shadow(
+	acl_checker: &AclChecker,
+	requester: Address,
+	document: H256,
+	decrypted_secret: Public,
+	common_point: Public,
+	shadows: Vec<Secret>,
+	encrypted_document: Bytes,
+) -> Result<Bytes, Error> {
+	let permitted = acl_checker.check(requester, &document)
+		.map_err(|e| errors::internal("ACL check failed", e))?;
+	if !permitted {
+		return Err(errors::permission_denied());
+	}
+
 	let key = decrypt_with_shadow_coefficients(decrypted_secret, common_point, shadows)?;
 	decrypt_document(key.to_vec(), encrypted_document)
 }
 
-fn into_document_key(key: Bytes) -> Result<Bytes, Error> {
+/// Run `encrypt_document` on `pool` instead of the calling (reactor) thread.
+pub fn encrypt_document_async(pool: &CpuPool, key: Bytes, document: Bytes) -> Box<Future<Item = Bytes, Error = Error> + Send> {
+	Box::new(pool.spawn_fn(move || encrypt_document(key, document)))
+}
+
+/// Run `decrypt_document` on `pool` instead of the calling (reactor) thread.
+pub fn decrypt_document_async(pool: &CpuPool, key: Bytes, encrypted_document: Bytes) -> Box<Future<Item = Bytes, Error = Error> + Send> {
+	Box::new(pool.spawn_fn(move || decrypt_document(key, encrypted_document)))
+}
+
+/// Run `decrypt_document_with_shadow` (including the ACL check) on `pool` instead of the
+/// calling (reactor) thread. Takes `acl_checker` as an `Arc` rather than a borrow, since
+/// the work is handed off to a worker thread and may outlive the caller's stack frame.
+pub fn decrypt_document_with_shadow_async(
+	pool: &CpuPool,
+	acl_checker: Arc<AclChecker>,
+	requester: Address,
+	document: H256,
+	decrypted_secret: Public,
+	common_point: Public,
+	shadows: Vec<Secret>,
+	encrypted_document: Bytes,
+) -> Box<Future<Item = Bytes, Error = Error> + Send> {
+	Box::new(pool.spawn_fn(move || decrypt_document_with_shadow(
+		&*acl_checker, requester, document, decrypted_secret, common_point, shadows, encrypted_document
+	)))
+}
+
+fn into_legacy_document_key(key: &[u8]) -> Result<Bytes, Error> {
 	// key is a previously distributely generated Public
 	if key.len() != 64 {
 		return Err(errors::invalid_params("key", "invalid public key length"));
@@ -75,6 +270,30 @@ fn into_document_key(key: Bytes) -> Result<Bytes, Error> {
 	Ok(key[..INIT_VEC_LEN].into())
 }
 
+/// Derive an AES-256 cipher key and a domain-separated MAC key from the whole
+/// distributely generated Public (as opposed to the legacy scheme, which only used
+/// the first 16 bytes for an AES-128 key).
+fn derive_keys(key: &[u8]) -> Result<(Bytes, Bytes), Error> {
+	if key.len() != 64 {
+		return Err(errors::invalid_params("key", "invalid public key length"));
+	}
+
+	let cipher_key = key.sha3().iter().cloned().collect();
+	let mac_key = [key, &[0x01u8][..]].concat().sha3().iter().cloned().collect();
+	Ok((cipher_key, mac_key))
+}
+
+/// Keccak-256 MAC of `data`, keyed by prefixing with `mac_key`.
+fn mac(mac_key: &[u8], data: &[u8]) -> Bytes {
+	[mac_key, data].concat().sha3().iter().cloned().collect()
+}
+
+/// Compare two equal-length byte strings without leaking timing information about
+/// the position of the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 fn initialization_vector() -> [u8; INIT_VEC_LEN] {
 	let mut result = [0u8; INIT_VEC_LEN];
 	let mut rng = OsRng::new().unwrap();
@@ -98,9 +317,36 @@ fn decrypt_with_shadow_coefficients(mut decrypted_shadow: Public, mut common_sha
 
 #[cfg(test)]
 mod tests {
-	use util::Bytes;
+	use std::sync::Arc;
+	use futures::Future;
+	use futures_cpupool::CpuPool;
+	use util::{Bytes, Address, H256};
 	use rustc_serialize::hex::FromHex;
-	use super::{encrypt_document, decrypt_document, decrypt_document_with_shadow};
+	use futures::future;
+	use super::{
+		AclChecker, AclContract, CallContract, encrypt_document, decrypt_document, decrypt_document_with_shadow,
+		encrypt_document_async, decrypt_document_async, decrypt_document_with_shadow_async,
+	};
+
+	struct AllowAll;
+	impl AclChecker for AllowAll {
+		fn check(&self, _requester: Address, _document: &H256) -> Result<bool, String> { Ok(true) }
+	}
+
+	struct DenyAll;
+	impl AclChecker for DenyAll {
+		fn check(&self, _requester: Address, _document: &H256) -> Result<bool, String> { Ok(false) }
+	}
+
+	/// Returns a fixed, ABI-encoded `bool` from every call, ignoring the request entirely.
+	struct FixedResponse(bool);
+	impl CallContract for FixedResponse {
+		fn call(&self, _contract: Address, _data: Bytes) -> Box<Future<Item = Bytes, Error = String> + Send> {
+			let mut response = vec![0u8; 32];
+			response[31] = self.0 as u8;
+			Box::new(future::ok(response))
+		}
+	}
 
 	#[test]
 	fn encrypt_and_decrypt_document() {
@@ -121,7 +367,95 @@ mod tests {
 		let decrypted_secret = "843645726384530ffb0c52f175278143b5a93959af7864460f5a4fec9afd1450cfb8aef63dec90657f43f55b13e0a73c7524d4e9a13c051b4e5f1e53f39ecd91".parse().unwrap();
 		let common_point = "07230e34ebfe41337d3ed53b186b3861751f2401ee74b988bba55694e2a6f60c757677e194be2e53c3523cc8548694e636e6acb35c4e8fdc5e29d28679b9b2f3".parse().unwrap();
 		let shadows = vec!["46f542416216f66a7d7881f5a283d2a1ab7a87b381cbc5f29d0b093c7c89ee31".parse().unwrap()];
-		let decrypted_document = decrypt_document_with_shadow(decrypted_secret, common_point, shadows, encrypted_document).unwrap();
+		let decrypted_document = decrypt_document_with_shadow(
+			&AllowAll, Address::zero(), H256::zero(), decrypted_secret, common_point, shadows, encrypted_document
+		).unwrap();
+		assert_eq!(decrypted_document, document);
+	}
+
+	#[test]
+	fn shadow_decrypt_document_rejects_unauthorized_requester() {
+		let encrypted_document = "2ddec1f96229efa2916988d8b2a82a47ef36f71c".from_hex().unwrap();
+		let decrypted_secret = "843645726384530ffb0c52f175278143b5a93959af7864460f5a4fec9afd1450cfb8aef63dec90657f43f55b13e0a73c7524d4e9a13c051b4e5f1e53f39ecd91".parse().unwrap();
+		let common_point = "07230e34ebfe41337d3ed53b186b3861751f2401ee74b988bba55694e2a6f60c757677e194be2e53c3523cc8548694e636e6acb35c4e8fdc5e29d28679b9b2f3".parse().unwrap();
+		let shadows = vec!["46f542416216f66a7d7881f5a283d2a1ab7a87b381cbc5f29d0b093c7c89ee31".parse().unwrap()];
+		let result = decrypt_document_with_shadow(
+			&DenyAll, Address::zero(), H256::zero(), decrypted_secret, common_point, shadows, encrypted_document
+		);
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn decrypt_document_rejects_tampered_ciphertext() {
+		let document_key: Bytes = "cac6c205eb06c8308d65156ff6c862c62b000b8ead121a4455a8ddeff7248128d895692136f240d5d1614dc7cc4147b1bd584bd617e30560bb872064d09ea325".from_hex().unwrap();
+		let document: Bytes = b"Hello, world!!!"[..].into();
+
+		let mut encrypted_document = encrypt_document(document_key.clone(), document.clone()).unwrap();
+		let last = encrypted_document.len() - 1;
+		encrypted_document[last] ^= 0xff;
+
+		// the MAC no longer verifies; decryption must error rather than silently fall back
+		// to the legacy layout, or a tampered authenticated document would decrypt
+		// "successfully" into garbage instead of being rejected
+		assert!(decrypt_document(document_key, encrypted_document).is_err());
+	}
+
+	#[test]
+	fn decrypt_document_rejects_legacy_documents_colliding_with_the_version_byte() {
+		let document_key: Bytes = "cac6c205eb06c8308d65156ff6c862c62b000b8ead121a4455a8ddeff7248128d895692136f240d5d1614dc7cc4147b1bd584bd617e30560bb872064d09ea325".from_hex().unwrap();
+		let document: Bytes = b"a legacy document long enough to collide with the version tag"[..].into();
+
+		// build a legacy-layout document (ciphertext || iv, no version byte, no MAC) and
+		// force its first byte to collide with `DOCUMENT_VERSION`, as happens for roughly
+		// one in 256 already-stored legacy documents. This is the known, accepted false
+		// positive documented on `decrypt_document`: the document is misrouted into the
+		// authenticated path and rejected, even though it was never tampered with.
+		let legacy_key = super::into_legacy_document_key(&document_key).unwrap();
+		let iv = [0u8; super::INIT_VEC_LEN];
+		let mut ciphertext = vec![0u8; document.len()];
+		::crypto::aes::encrypt(&legacy_key, &iv, &document, &mut ciphertext);
+		ciphertext[0] = super::DOCUMENT_VERSION;
+
+		let mut legacy_document = ciphertext;
+		legacy_document.extend_from_slice(&iv);
+
+		assert!(decrypt_document(document_key, legacy_document).is_err());
+	}
+
+	#[test]
+	fn encrypt_and_decrypt_document_async_roundtrip() {
+		let pool = CpuPool::new(1);
+		let document_key: Bytes = "cac6c205eb06c8308d65156ff6c862c62b000b8ead121a4455a8ddeff7248128d895692136f240d5d1614dc7cc4147b1bd584bd617e30560bb872064d09ea325".from_hex().unwrap();
+		let document: Bytes = b"Hello, world!!!"[..].into();
+
+		let encrypted_document = encrypt_document_async(&pool, document_key.clone(), document.clone()).wait().unwrap();
+		let decrypted_document = decrypt_document_async(&pool, document_key, encrypted_document).wait().unwrap();
 		assert_eq!(decrypted_document, document);
 	}
+
+	#[test]
+	fn shadow_decrypt_document_async_checks_acl() {
+		let pool = CpuPool::new(1);
+		let encrypted_document = "2ddec1f96229efa2916988d8b2a82a47ef36f71c".from_hex().unwrap();
+		let decrypted_secret = "843645726384530ffb0c52f175278143b5a93959af7864460f5a4fec9afd1450cfb8aef63dec90657f43f55b13e0a73c7524d4e9a13c051b4e5f1e53f39ecd91".parse().unwrap();
+		let common_point = "07230e34ebfe41337d3ed53b186b3861751f2401ee74b988bba55694e2a6f60c757677e194be2e53c3523cc8548694e636e6acb35c4e8fdc5e29d28679b9b2f3".parse().unwrap();
+		let shadows = vec!["46f542416216f66a7d7881f5a283d2a1ab7a87b381cbc5f29d0b093c7c89ee31".parse().unwrap()];
+
+		let result = decrypt_document_with_shadow_async(
+			&pool, Arc::new(DenyAll), Address::zero(), H256::zero(), decrypted_secret, common_point, shadows, encrypted_document
+		).wait();
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn acl_contract_decodes_permitted_response() {
+		let acl = AclContract::new(Address::zero(), FixedResponse(true));
+		assert_eq!(acl.check(Address::zero(), &H256::zero()), Ok(true));
+	}
+
+	#[test]
+	fn acl_contract_decodes_denied_response() {
+		let acl = AclContract::new(Address::zero(), FixedResponse(false));
+		assert_eq!(acl.check(Address::zero(), &H256::zero()), Ok(false));
+	}
 }